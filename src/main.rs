@@ -1,12 +1,57 @@
-use std::{ffi::OsString, path::PathBuf};
+use std::{
+    ffi::{OsStr, OsString},
+    path::{Path, PathBuf},
+};
 
 use clap::{builder::TypedValueParser, Parser};
+use walkdir::WalkDir;
 
 #[derive(Parser, Debug)]
 #[clap(version, about = "command line helper to work with file extensions", long_about = None)]
 struct Args {
     #[clap(subcommand)]
     action: Action,
+
+    /// Preview the renames that would happen without touching the filesystem.
+    #[clap(short = 'n', long, global = true, action)]
+    dry_run: bool,
+
+    /// Move a colliding destination aside with a numbered backup suffix instead of
+    /// overwriting it. Takes an optional extension to use instead of `bak`.
+    #[clap(
+        short = 'b',
+        long,
+        global = true,
+        num_args = 0..=1,
+        default_missing_value = "bak",
+        value_parser = ExtensionParser,
+        conflicts_with = "no_clobber"
+    )]
+    backup: Option<OsString>,
+
+    /// Skip a rename instead of overwriting an existing file at the destination.
+    #[clap(long, global = true, action)]
+    no_clobber: bool,
+
+    /// Recurse into directories given among the file arguments.
+    #[clap(short = 'r', long, global = true, action)]
+    recursive: bool,
+
+    /// Only include files with this extension, whether or not `--recursive` is set.
+    #[clap(long = "match", global = true, value_parser = ExtensionParser)]
+    match_ext: Option<OsString>,
+
+    /// Validate the whole rename plan up front and roll back completed renames if one
+    /// fails partway through, instead of applying renames one at a time.
+    #[clap(short = 't', long, global = true, action)]
+    transactional: bool,
+}
+
+/// How to handle a rename whose destination already exists.
+enum CollisionMode {
+    Clobber,
+    Backup(OsString),
+    NoClobber,
 }
 
 #[derive(Clone, Debug)]
@@ -97,6 +142,17 @@ enum Action {
         #[clap(value_parser = ExtensionParser)]
         extension: OsString,
 
+        /// List of files to change.
+        #[clap(value_parser, required = true)]
+        files: Vec<PathBuf>,
+    },
+    /// Normalizes the casing of each file's extension, lowercasing it by default.
+    #[clap(visible_alias = "n", visible_alias = "norm")]
+    Normalize {
+        /// Uppercase the extension instead of lowercasing it.
+        #[clap(long, action)]
+        upper: bool,
+
         /// List of files to change.
         #[clap(value_parser, required = true)]
         files: Vec<PathBuf>,
@@ -117,13 +173,38 @@ enum And {
     },
 }
 
-fn is_file(pb: &&PathBuf) -> bool {
-    pb.is_file()
-}
+/// Expands `files` into a flat list of regular files, descending into directories when
+/// `recursive` is set and, if `match_ext` is given, keeping only files with that extension.
+fn get_files(
+    files: &[PathBuf],
+    recursive: bool,
+    match_ext: Option<&OsStr>,
+) -> Result<Vec<PathBuf>, &'static str> {
+    let mut expanded = Vec::new();
+    for path in files {
+        if recursive && path.is_dir() {
+            for entry in WalkDir::new(path)
+                .into_iter()
+                .filter_map(|entry| entry.ok())
+                .filter(|entry| entry.file_type().is_file())
+            {
+                expanded.push(entry.into_path());
+            }
+        } else {
+            expanded.push(path.clone());
+        }
+    }
 
-fn get_files(files: &[PathBuf]) -> Result<Vec<&PathBuf>, &str> {
-    let files = files.into_iter().filter(is_file).collect::<Vec<_>>();
-    if files.len() == 0 {
+    let mut files = expanded
+        .into_iter()
+        .filter(|path| path.is_file())
+        .collect::<Vec<_>>();
+
+    if let Some(match_ext) = match_ext {
+        files.retain(|path| path.extension() == Some(match_ext));
+    }
+
+    if files.is_empty() {
         Err("No files match filter!")
     } else {
         Ok(files)
@@ -145,95 +226,497 @@ fn append_extension(path: &PathBuf, extension: &OsString, force: bool) -> PathBu
     new_name
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let args = Args::parse();
+type Plan = Vec<(PathBuf, PathBuf)>;
 
-    match args.action {
-        Action::ToggleBetween {
-            extension1,
-            and: And::And { extension2, files },
-        } => {
-            let mut new_files = Vec::with_capacity(files.len() * 2);
-            for file in files {
-                new_files.push(file.clone());
-                if let Some(ext) = file.extension() {
-                    if ext == extension1 {
-                        new_files.push(file.with_extension(&extension2));
-                    } else if ext == extension2 {
-                        new_files.push(file.with_extension(&extension1));
-                    }
-                } else {
-                    new_files.push(file.with_extension(&extension1));
-                    new_files.push(file.with_extension(&extension2));
+/// Moves `path` out of the way under a numbered backup suffix (`<name>.<backup_ext>`,
+/// then `<name>.<backup_ext>.0`, `<name>.<backup_ext>.1`, ...) and returns where it went.
+fn move_aside(path: &Path, backup_ext: &OsStr) -> std::io::Result<PathBuf> {
+    let mut base = path.file_name().unwrap_or_default().to_os_string();
+    base.push(".");
+    base.push(backup_ext);
+
+    let mut candidate = path.with_file_name(&base);
+    let mut suffix = 0u32;
+    while candidate.exists() {
+        let mut next = base.clone();
+        next.push(".");
+        next.push(suffix.to_string());
+        candidate = path.with_file_name(&next);
+        suffix += 1;
+    }
+
+    std::fs::rename(path, &candidate)?;
+    Ok(candidate)
+}
+
+/// Checks that `old` can actually be renamed: it exists, isn't read-only, and its parent
+/// directory isn't read-only either. This is a best-effort check (it can't account for
+/// every OS-specific permission rule) but catches the common cases up front.
+fn check_renamable(old: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let metadata =
+        std::fs::metadata(old).map_err(|err| format!("cannot access {:?}: {}", old, err))?;
+    if metadata.permissions().readonly() {
+        return Err(format!("{:?} is read-only and can't be renamed", old).into());
+    }
+
+    let parent = old
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+        .unwrap_or_else(|| Path::new("."));
+    let parent_metadata = std::fs::metadata(parent)
+        .map_err(|err| format!("cannot access directory {:?}: {}", parent, err))?;
+    if parent_metadata.permissions().readonly() {
+        return Err(format!("directory {:?} is read-only", parent).into());
+    }
+
+    Ok(())
+}
+
+/// Checks a rename plan for problems before anything is touched: two sources mapping to
+/// the same destination, a destination that collides with a file outside the plan (only in
+/// clobber mode, where that file would be overwritten), and sources that can't be renamed
+/// (see [`check_renamable`]).
+fn validate_plan(plan: &Plan, collision: &CollisionMode) -> Result<(), Box<dyn std::error::Error>> {
+    let sources: std::collections::HashSet<&PathBuf> = plan.iter().map(|(old, _)| old).collect();
+
+    let mut destinations = std::collections::HashMap::new();
+    for (old, new) in plan {
+        if let Some(other) = destinations.insert(new, old) {
+            return Err(format!(
+                "both {:?} and {:?} would be renamed to {:?}",
+                other, old, new
+            )
+            .into());
+        }
+    }
+
+    for (old, new) in plan {
+        if matches!(collision, CollisionMode::Clobber) && new.exists() && !sources.contains(new) {
+            return Err(format!(
+                "{:?} would overwrite {:?}, which isn't part of this rename",
+                old, new
+            )
+            .into());
+        }
+
+        check_renamable(old)?;
+    }
+
+    Ok(())
+}
+
+/// A rename the transaction has already applied, including where the occupant of `new`
+/// (if any) was backed up to, so it can be put back during a rollback.
+struct AppliedRename {
+    old: PathBuf,
+    new: PathBuf,
+    backed_up: Option<PathBuf>,
+}
+
+/// Applies a rename plan, recording each completed move, and reverses everything already
+/// applied (in LIFO order) if one of the renames, or moving a destination aside, fails
+/// partway through. A backup made for the in-flight rename that failed is restored too, so
+/// a failed transaction leaves the directory as it was found, except for any file that a
+/// rename in clobber mode overwrote: its old contents are not brought back.
+fn apply_transactional(
+    plan: Plan,
+    collision: &CollisionMode,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut applied: Vec<AppliedRename> = Vec::new();
+
+    for (old_path, new_path) in plan {
+        let mut backed_up = None;
+        let mut result = Ok(());
+
+        if new_path.exists() {
+            match collision {
+                CollisionMode::Clobber => {}
+                CollisionMode::NoClobber => continue,
+                CollisionMode::Backup(backup_ext) => match move_aside(&new_path, backup_ext) {
+                    Ok(backup_path) => backed_up = Some(backup_path),
+                    Err(err) => result = Err(err),
+                },
+            }
+        }
+
+        if let Err(err) = result.and_then(|()| std::fs::rename(&old_path, &new_path)) {
+            if let Some(backup_path) = &backed_up {
+                std::fs::rename(backup_path, &new_path).ok();
+            }
+
+            let rolled_back = applied.len();
+            for entry in applied.into_iter().rev() {
+                std::fs::rename(&entry.new, &entry.old).ok();
+                if let Some(backup_path) = entry.backed_up {
+                    std::fs::rename(&backup_path, &entry.new).ok();
                 }
             }
-            let paths = get_files(&new_files)?;
-            for path in paths {
-                if let Some(ext) = path.extension() {
-                    if extension1 == ext {
-                        std::fs::rename(&path, path.with_extension(&extension2))?
-                    } else if extension2 == ext {
-                        std::fs::rename(&path, path.with_extension(&extension1))?
+
+            return Err(format!(
+                "renaming {:?} -> {:?} failed ({}), rolled back {} completed rename(s)",
+                old_path, new_path, err, rolled_back
+            )
+            .into());
+        }
+
+        applied.push(AppliedRename {
+            old: old_path,
+            new: new_path,
+            backed_up,
+        });
+    }
+
+    Ok(())
+}
+
+/// Applies a rename plan, or just prints it when `dry_run` is set. In transactional mode
+/// the plan is validated up front and rolled back as a whole on failure.
+fn execute_plan(
+    plan: Plan,
+    dry_run: bool,
+    collision: &CollisionMode,
+    transactional: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if transactional {
+        validate_plan(&plan, collision)?;
+    }
+
+    if dry_run {
+        for (old_path, new_path) in plan {
+            if new_path.exists() {
+                match collision {
+                    CollisionMode::Clobber => {}
+                    CollisionMode::NoClobber => {
+                        println!(
+                            "skipping {:?} -> {:?} (destination exists)",
+                            old_path, new_path
+                        );
+                        continue;
+                    }
+                    CollisionMode::Backup(_) => {
+                        println!("backing up existing {:?} before renaming", new_path);
                     }
                 }
             }
+            println!("renaming {:?} -> {:?}", old_path, new_path);
         }
-        Action::Toggle { files, extension } => {
-            let mut new_files = Vec::with_capacity(files.len() * 2);
-            for file in files {
-                new_files.push(file.clone());
-                new_files.push(append_extension(&file, &extension, false));
-            }
-            let paths = get_files(&new_files)?;
-            for path in paths {
-                if let Some(ext) = path.extension() {
-                    if extension == ext {
-                        if let Some(new_path) = path.file_stem() {
-                            std::fs::rename(&path, path.with_file_name(new_path))?
-                        }
-                    } else {
-                        let new_name = append_extension(path, &extension, false);
-                        std::fs::rename(&path, new_name)?
-                    }
+        return Ok(());
+    }
+
+    if transactional {
+        return apply_transactional(plan, collision);
+    }
+
+    for (old_path, new_path) in plan {
+        if new_path.exists() {
+            match collision {
+                CollisionMode::Clobber => {}
+                CollisionMode::NoClobber => continue,
+                CollisionMode::Backup(backup_ext) => {
+                    move_aside(&new_path, backup_ext)?;
                 }
             }
         }
-        Action::Add {
-            files,
-            extension,
-            force,
-        } => {
-            let paths = get_files(&files)?;
-            for path in paths {
-                let new_name = append_extension(path, &extension, force);
-                std::fs::rename(&path, new_name)?
+
+        std::fs::rename(&old_path, &new_path)?;
+    }
+    Ok(())
+}
+
+fn build_toggle_between_plan(
+    extension1: OsString,
+    extension2: OsString,
+    files: Vec<PathBuf>,
+    recursive: bool,
+    match_ext: Option<&OsStr>,
+) -> Result<Plan, Box<dyn std::error::Error>> {
+    let mut new_files = Vec::with_capacity(files.len() * 2);
+    for file in files {
+        new_files.push(file.clone());
+        if let Some(ext) = file.extension() {
+            if ext == extension1 {
+                new_files.push(file.with_extension(&extension2));
+            } else if ext == extension2 {
+                new_files.push(file.with_extension(&extension1));
             }
+        } else {
+            new_files.push(file.with_extension(&extension1));
+            new_files.push(file.with_extension(&extension2));
         }
-        Action::Set { files, extension } => {
-            let paths = get_files(&files)?;
-            for path in paths {
-                std::fs::rename(&path, path.with_extension(&extension))?;
+    }
+    let paths = get_files(&new_files, recursive, match_ext)?;
+    let mut plan = Vec::new();
+    for path in paths {
+        if let Some(ext) = path.extension() {
+            if extension1 == ext {
+                plan.push((path.clone(), path.with_extension(&extension2)));
+            } else if extension2 == ext {
+                plan.push((path.clone(), path.with_extension(&extension1)));
             }
         }
-        Action::Remove { files, extension } => {
-            let paths = get_files(&files)?;
-            for path in paths {
-                if extension.is_empty() {
-                    if let Some(new_path) = path.file_stem() {
-                        std::fs::rename(&path, path.with_file_name(new_path))?
-                    }
-                } else {
-                    if let Some(ext) = path.extension() {
-                        if extension == ext {
-                            if let Some(new_path) = path.file_stem() {
-                                std::fs::rename(&path, path.with_file_name(new_path))?
-                            }
-                        }
-                    }
+    }
+    Ok(plan)
+}
+
+fn build_toggle_plan(
+    files: Vec<PathBuf>,
+    extension: OsString,
+    recursive: bool,
+    match_ext: Option<&OsStr>,
+) -> Result<Plan, Box<dyn std::error::Error>> {
+    let mut new_files = Vec::with_capacity(files.len() * 2);
+    for file in files {
+        new_files.push(file.clone());
+        new_files.push(append_extension(&file, &extension, false));
+    }
+    let paths = get_files(&new_files, recursive, match_ext)?;
+    let mut plan = Vec::new();
+    for path in paths {
+        if let Some(ext) = path.extension() {
+            if extension == ext {
+                if let Some(new_path) = path.file_stem() {
+                    plan.push((path.clone(), path.with_file_name(new_path)));
                 }
+            } else {
+                let new_name = append_extension(&path, &extension, false);
+                plan.push((path.clone(), new_name));
             }
         }
     }
+    Ok(plan)
+}
+
+fn build_add_plan(
+    files: Vec<PathBuf>,
+    extension: OsString,
+    force: bool,
+    recursive: bool,
+    match_ext: Option<&OsStr>,
+) -> Result<Plan, Box<dyn std::error::Error>> {
+    let paths = get_files(&files, recursive, match_ext)?;
+    let mut plan = Vec::new();
+    for path in paths {
+        let new_name = append_extension(&path, &extension, force);
+        plan.push((path.clone(), new_name));
+    }
+    Ok(plan)
+}
+
+fn build_set_plan(
+    files: Vec<PathBuf>,
+    extension: OsString,
+    recursive: bool,
+    match_ext: Option<&OsStr>,
+) -> Result<Plan, Box<dyn std::error::Error>> {
+    let paths = get_files(&files, recursive, match_ext)?;
+    let mut plan = Vec::new();
+    for path in paths {
+        plan.push((path.clone(), path.with_extension(&extension)));
+    }
+    Ok(plan)
+}
+
+fn build_remove_plan(
+    files: Vec<PathBuf>,
+    extension: OsString,
+    recursive: bool,
+    match_ext: Option<&OsStr>,
+) -> Result<Plan, Box<dyn std::error::Error>> {
+    let paths = get_files(&files, recursive, match_ext)?;
+    let mut plan = Vec::new();
+    for path in paths {
+        if extension.is_empty() {
+            if let Some(new_path) = path.file_stem() {
+                plan.push((path.clone(), path.with_file_name(new_path)));
+            }
+        } else if let Some(ext) = path.extension() {
+            if extension == ext {
+                if let Some(new_path) = path.file_stem() {
+                    plan.push((path.clone(), path.with_file_name(new_path)));
+                }
+            }
+        }
+    }
+    Ok(plan)
+}
+
+fn build_normalize_plan(
+    files: Vec<PathBuf>,
+    upper: bool,
+    recursive: bool,
+    match_ext: Option<&OsStr>,
+) -> Result<Plan, Box<dyn std::error::Error>> {
+    let paths = get_files(&files, recursive, match_ext)?;
+    let mut plan = Vec::new();
+    for path in paths {
+        if let Some(ext) = path.extension() {
+            let ext = ext.to_string_lossy();
+            let normalized = if upper {
+                ext.to_uppercase()
+            } else {
+                ext.to_lowercase()
+            };
+            if normalized != ext.as_ref() {
+                plan.push((path.clone(), path.with_extension(normalized)));
+            }
+        }
+    }
+    Ok(plan)
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Args::parse();
+
+    let recursive = args.recursive;
+    let match_ext = args.match_ext.as_deref();
+
+    let plan = match args.action {
+        Action::ToggleBetween {
+            extension1,
+            and: And::And { extension2, files },
+        } => build_toggle_between_plan(extension1, extension2, files, recursive, match_ext)?,
+        Action::Toggle { files, extension } => {
+            build_toggle_plan(files, extension, recursive, match_ext)?
+        }
+        Action::Add {
+            files,
+            extension,
+            force,
+        } => build_add_plan(files, extension, force, recursive, match_ext)?,
+        Action::Set { files, extension } => build_set_plan(files, extension, recursive, match_ext)?,
+        Action::Remove { files, extension } => {
+            build_remove_plan(files, extension, recursive, match_ext)?
+        }
+        Action::Normalize { files, upper } => {
+            build_normalize_plan(files, upper, recursive, match_ext)?
+        }
+    };
+
+    let collision = if args.no_clobber {
+        CollisionMode::NoClobber
+    } else if let Some(backup_ext) = args.backup {
+        CollisionMode::Backup(backup_ext)
+    } else {
+        CollisionMode::Clobber
+    };
+
+    execute_plan(plan, args.dry_run, &collision, args.transactional)?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn move_aside_picks_next_free_suffix() {
+        let dir =
+            std::env::temp_dir().join(format!("xtend_test_move_aside_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let target = dir.join("a.png");
+        std::fs::write(&target, b"original").unwrap();
+        std::fs::write(dir.join("a.png.bak"), b"already taken").unwrap();
+
+        let moved = move_aside(&target, OsStr::new("bak")).unwrap();
+
+        assert_eq!(moved, dir.join("a.png.bak.0"));
+        assert!(!target.exists());
+        assert!(moved.exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn validate_plan_detects_duplicate_destination() {
+        let plan: Plan = vec![
+            (PathBuf::from("a.txt"), PathBuf::from("c.txt")),
+            (PathBuf::from("b.txt"), PathBuf::from("c.txt")),
+        ];
+
+        let result = validate_plan(&plan, &CollisionMode::Clobber);
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn validate_plan_succeeds_for_bare_relative_paths() {
+        let old = PathBuf::from(format!(
+            "xtend_test_validate_plan_{}.txt",
+            std::process::id()
+        ));
+        std::fs::write(&old, b"content").unwrap();
+
+        let new = PathBuf::from(format!(
+            "xtend_test_validate_plan_{}.bak",
+            std::process::id()
+        ));
+        let plan: Plan = vec![(old.clone(), new)];
+
+        let result = validate_plan(&plan, &CollisionMode::Clobber);
+
+        std::fs::remove_file(&old).unwrap();
+
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn build_set_plan_maps_each_file_to_the_new_extension() {
+        let dir =
+            std::env::temp_dir().join(format!("xtend_test_build_set_plan_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("a.jpg"), b"").unwrap();
+        std::fs::write(dir.join("b.jpg"), b"").unwrap();
+
+        let files = vec![dir.join("a.jpg"), dir.join("b.jpg")];
+        let plan = build_set_plan(files, OsString::from("png"), false, None).unwrap();
+
+        let mut plan = plan;
+        plan.sort();
+        assert_eq!(
+            plan,
+            vec![
+                (dir.join("a.jpg"), dir.join("a.png")),
+                (dir.join("b.jpg"), dir.join("b.png")),
+            ]
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn get_files_recurses_into_directories_and_applies_match_ext() {
+        let dir = std::env::temp_dir().join(format!("xtend_test_get_files_{}", std::process::id()));
+        let nested = dir.join("nested");
+        std::fs::create_dir_all(&nested).unwrap();
+        std::fs::write(dir.join("a.jpg"), b"").unwrap();
+        std::fs::write(nested.join("b.jpg"), b"").unwrap();
+        std::fs::write(nested.join("c.png"), b"").unwrap();
+
+        let mut found = get_files(&[dir.clone()], true, Some(OsStr::new("jpg"))).unwrap();
+        found.sort();
+
+        assert_eq!(found, vec![dir.join("a.jpg"), nested.join("b.jpg")]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn build_normalize_plan_lowercases_and_skips_already_correct_case() {
+        let dir = std::env::temp_dir().join(format!(
+            "xtend_test_build_normalize_plan_{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("IMG.JPG"), b"").unwrap();
+        std::fs::write(dir.join("already.jpg"), b"").unwrap();
+
+        let files = vec![dir.join("IMG.JPG"), dir.join("already.jpg")];
+        let plan = build_normalize_plan(files, false, false, None).unwrap();
+
+        assert_eq!(plan, vec![(dir.join("IMG.JPG"), dir.join("IMG.jpg"))]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}